@@ -2,53 +2,364 @@ use gstreamer as gst;
 use glib::prelude::*;
 use gst::prelude::*;
 use std::collections::VecDeque;
-use std::sync::Mutex;
+use std::sync::{Arc, Condvar, Mutex};
 use once_cell::sync::Lazy;
 
-// Example pipeline:
+// Example pipelines:
 // gst-launch-1.0 filesrc location=video.h264 ! h264parse ! prerollvalve open=true max-history=5000 ! h264parse ! avdec_h264 ! autovideosink
+//
+// Synchronized A/V preroll (request pads; "sink" is always the primary/video pad):
+// gst-launch-1.0 prerollvalve name=valve open=true max-history=5000 \
+//   valve.src ! h264parse ! avdec_h264 ! autovideosink \
+//   valve.src_0 ! aacparse ! avdec_aac ! autoaudiosink \
+//   filesrc location=video.h264 ! h264parse ! valve.sink \
+//   filesrc location=audio.aac ! aacparse ! valve.sink_0
 
 // Property defaults
 const DEFAULT_OPEN: bool = false;
+const DEFAULT_MIN_HISTORY: u64 = 0; // ms
 const DEFAULT_MAX_HISTORY: u64 = 5000; // ms
 const DEFAULT_DEBUG: bool = false;
+const DEFAULT_DUMP_FROM: u64 = 0; // UNIX ms, 0 = unbounded
+const DEFAULT_DUMP_TO: u64 = 0; // UNIX ms, 0 = unbounded
+const DEFAULT_MAX_SIZE_BYTES: u64 = 0; // 0 = disabled
+const DEFAULT_MAX_SIZE_BUFFERS: u64 = 0; // 0 = disabled
+
+// Seconds between the NTP epoch (1900-01-01) and the UNIX epoch
+// (1970-01-01), used to convert `timestamp/x-ntp` reference timestamps.
+const NTP_TO_UNIX_OFFSET_SECONDS: u64 = 2_208_988_800;
+
+// Which clock `State::prune` measures the backlog against. The dump-range
+// selection (`State::dump_range`) always uses UTC, whatever this is set to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, glib::Enum)]
+#[enum_type(name = "GstPrerollValveHistoryMode")]
+enum HistoryMode {
+    // Measure history against buffer PTS, relative to the stream head.
+    Pts,
+    // Measure history against UTC wall-clock time derived from
+    // `GstReferenceTimestampMeta`, falling back to PTS for buffers that
+    // don't carry one.
+    Utc,
+}
+
+impl Default for HistoryMode {
+    fn default() -> Self {
+        HistoryMode::Pts
+    }
+}
 
 // Properties
 #[derive(Debug, Clone, Copy)]
 struct Settings {
     open: bool,
+    min_history: u64,
     max_history: u64,
     debug: bool,
+    history_mode: HistoryMode,
+    dump_from: u64,
+    dump_to: u64,
+    max_size_bytes: u64,
+    max_size_buffers: u64,
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Self {
             open: DEFAULT_OPEN,
+            min_history: DEFAULT_MIN_HISTORY,
             max_history: DEFAULT_MAX_HISTORY,
             debug: DEFAULT_DEBUG,
+            history_mode: HistoryMode::default(),
+            dump_from: DEFAULT_DUMP_FROM,
+            dump_to: DEFAULT_DUMP_TO,
+            max_size_bytes: DEFAULT_MAX_SIZE_BYTES,
+            max_size_buffers: DEFAULT_MAX_SIZE_BUFFERS,
         }
     }
 }
 
+// Sticky events (in GStreamer's sense: STREAM_START/CAPS/SEGMENT/TAG) as
+// they stood at some point in time, so a keyframe deep in the backlog can
+// be dumped with the exact CAPS/SEGMENT pair that preceded it rather than
+// whatever happens to be current when the valve opens.
+#[derive(Debug, Clone, Default)]
+struct StickySnapshot {
+    stream_start: Option<gst::Event>,
+    caps: Option<gst::Event>,
+    segment: Option<gst::Event>,
+    tag: Option<gst::Event>,
+}
+
 struct StoredBuffer {
     buffer: gst::Buffer,
     timestamp: gst::ClockTime,
+    // UTC time derived from a `GstReferenceTimestampMeta` on `buffer`, if
+    // any (see `reference_utc`).
+    utc: Option<gst::ClockTime>,
     is_keyframe: bool,
+    // Sticky events current at the moment this buffer was stored, captured
+    // only for keyframes since those are the only buffers a dump can ever
+    // start from.
+    sticky: Option<StickySnapshot>,
+}
+
+impl StoredBuffer {
+    // The timestamp to measure history/range windows against, per
+    // `history_mode`. Buffers without a UTC reference always fall back to
+    // PTS, even in `HistoryMode::Utc`.
+    fn reference_ts(&self, mode: HistoryMode) -> gst::ClockTime {
+        match mode {
+            HistoryMode::Pts => self.timestamp,
+            HistoryMode::Utc => self.utc.unwrap_or(self.timestamp),
+        }
+    }
+}
+
+// Read the UTC time a buffer was produced at from its
+// `GstReferenceTimestampMeta`, if present. Recognizes `timestamp/x-unix`
+// directly and `timestamp/x-ntp` by subtracting the NTP->UNIX epoch offset,
+// mirroring mp4mux/fmp4mux. Returns `None` for buffers without such a meta,
+// or with a meta referencing neither caps.
+fn reference_utc(buffer: &gst::Buffer) -> Option<gst::ClockTime> {
+    let meta = buffer.meta::<gst::ReferenceTimestampMeta>()?;
+    let structure = meta.reference().structure(0)?;
+
+    if structure.name() == "timestamp/x-unix" {
+        Some(meta.timestamp())
+    } else if structure.name() == "timestamp/x-ntp" {
+        let ntp = meta.timestamp();
+        let offset = gst::ClockTime::from_seconds(NTP_TO_UNIX_OFFSET_SECONDS);
+        if ntp >= offset {
+            Some(ntp - offset)
+        } else {
+            None
+        }
+    } else {
+        None
+    }
 }
 
+// State is tracked as a flat buffer queue plus the index of every buffer
+// that starts a new Group-of-Pictures (i.e. every keyframe). Pruning only
+// ever drops whole GOPs from the front, so the queue can never start on a
+// delta frame: the dump path always has a leading keyframe to work with.
+//
+// One `State` is kept per stream pad (see `StreamPad`); streams without a
+// real GOP structure (e.g. audio) mark every buffer as a keyframe, which
+// degenerates this to plain per-buffer time pruning.
 struct State {
     queue: VecDeque<StoredBuffer>,
+    // Index (into `queue`) of each keyframe currently stored, in order.
+    gop_starts: VecDeque<usize>,
+    // Number of buffers permanently dropped from the front of `queue` so
+    // far, used to translate absolute indices in `gop_starts` to the
+    // current `queue` offsets.
+    dropped: usize,
+    // Running total of `buffer.size()` for everything currently in `queue`,
+    // kept up to date on push (`buffer_and_prune`) and pop (`pop_front_gop`)
+    // so `max-size-bytes`/`current-level-bytes` are O(1) to check/report.
+    cur_bytes: u64,
 }
 
 impl Default for State {
     fn default() -> Self {
         Self {
             queue: VecDeque::new(),
+            gop_starts: VecDeque::new(),
+            dropped: 0,
+            cur_bytes: 0,
+        }
+    }
+}
+
+impl State {
+    // Translate an absolute buffer index (as stored in `gop_starts`) into
+    // the current offset within `queue`.
+    fn offset(&self, abs_index: usize) -> usize {
+        abs_index - self.dropped
+    }
+
+    // Record a newly-arrived buffer. `pop_front_gop`/`prune` assume the
+    // front of `queue` is always the keyframe at `gop_starts[0]` (offset
+    // 0), so a dump never starts mid-GOP. That invariant only needs
+    // restoring when the first keyframe since `gop_starts` was last emptied
+    // (at start, or after a dump) arrives: if this is that keyframe and
+    // delta frames already sit ahead of it (buffered while the valve was
+    // closed mid-GOP), those deltas can never anchor a dump themselves
+    // (`queue_dump` only replays a keyframe's sticky snapshot) and must be
+    // dropped now, or `gop_starts`/`dropped` desync from the real queue
+    // contents on the next prune.
+    fn push_buffer(&mut self, stored: StoredBuffer) {
+        if stored.is_keyframe && self.gop_starts.is_empty() && !self.queue.is_empty() {
+            self.dropped += self.queue.len();
+            for orphan in self.queue.drain(..) {
+                self.cur_bytes = self.cur_bytes.saturating_sub(orphan.buffer.size() as u64);
+            }
+        }
+
+        if stored.is_keyframe {
+            self.gop_starts.push_back(self.dropped + self.queue.len());
+        }
+        self.cur_bytes += stored.buffer.size() as u64;
+        self.queue.push_back(stored);
+    }
+
+    // Drop the whole front GOP (from the first keyframe up to, but not
+    // including, the next keyframe, or the end of the queue if this is the
+    // last GOP). Returns the total size in bytes of the dropped buffers.
+    fn pop_front_gop(&mut self) -> u64 {
+        let front_abs = match self.gop_starts.pop_front() {
+            Some(idx) => idx,
+            None => return 0,
+        };
+        let end_abs = self.gop_starts.front().copied().unwrap_or(front_abs + self.queue.len());
+        let front_off = self.offset(front_abs);
+        let end_off = self.offset(end_abs);
+
+        let mut freed_bytes = 0u64;
+        for _ in front_off..end_off {
+            if let Some(stored) = self.queue.pop_front() {
+                freed_bytes += stored.buffer.size() as u64;
+            }
+        }
+        self.dropped += end_off - front_off;
+        self.cur_bytes = self.cur_bytes.saturating_sub(freed_bytes);
+        freed_bytes
+    }
+
+    // Select the keyframe-aligned [start, end) range of `queue` indices to
+    // release on open. With no bounds this is "from the first keyframe to
+    // the end of the backlog"; with `from`/`to` set, it's the smallest run
+    // of whole GOPs covering that absolute UTC window. Range bounds are
+    // always measured in UTC (falling back to PTS per `reference_ts`),
+    // independent of the pruning `history_mode`.
+    fn dump_range(&self, from: Option<gst::ClockTime>, to: Option<gst::ClockTime>) -> (usize, usize) {
+        if self.gop_starts.is_empty() {
+            return (0, self.queue.len());
+        }
+
+        let gop_offsets: Vec<usize> = self.gop_starts.iter().map(|&abs| self.offset(abs)).collect();
+
+        let start = match from {
+            None => gop_offsets[0],
+            Some(from) => gop_offsets
+                .iter()
+                .copied()
+                .take_while(|&off| self.queue[off].reference_ts(HistoryMode::Utc) <= from)
+                .last()
+                .unwrap_or(gop_offsets[0]),
+        };
+
+        let end = match to {
+            None => self.queue.len(),
+            Some(to) => gop_offsets
+                .iter()
+                .copied()
+                .find(|&off| self.queue[off].reference_ts(HistoryMode::Utc) > to)
+                .unwrap_or(self.queue.len()),
+        };
+
+        (start, end.max(start))
+    }
+
+    // Prune whole GOPs from the front: only ever drop a GOP once doing so
+    // would still leave at least `settings.min_history` of complete,
+    // keyframe-started backlog behind, and only while the backlog still
+    // exceeds `settings.max_history` (time) or the hard
+    // `max-size-bytes`/`max-size-buffers` caps, if set. `min_history` only
+    // protects the time-based bound; the byte/count caps are hard memory
+    // limits and always win, same as mp4mux's interleave limits.
+    fn prune(&mut self, settings: &Settings, current_ts: gst::ClockTime) {
+        let min_history = gst::ClockTime::from_mseconds(settings.min_history);
+        let max_history = gst::ClockTime::from_mseconds(settings.max_history);
+
+        while self.gop_starts.len() >= 2 {
+            let front_off = self.offset(self.gop_starts[0]);
+            let next_off = self.offset(self.gop_starts[1]);
+            let front_ts = self.queue[front_off].reference_ts(settings.history_mode);
+            let next_ts = self.queue[next_off].reference_ts(settings.history_mode);
+
+            let exceeds_time = current_ts > front_ts && (current_ts - front_ts) > max_history;
+            let exceeds_bytes = settings.max_size_bytes != 0 && self.cur_bytes > settings.max_size_bytes;
+            let exceeds_buffers =
+                settings.max_size_buffers != 0 && self.queue.len() as u64 > settings.max_size_buffers;
+
+            if !(exceeds_time || exceeds_bytes || exceeds_buffers) {
+                break;
+            }
+
+            if exceeds_time && !exceeds_bytes && !exceeds_buffers {
+                let retains_min = current_ts > next_ts && (current_ts - next_ts) >= min_history;
+                if !retains_min {
+                    break;
+                }
+            }
+
+            self.pop_front_gop();
+        }
+    }
+
+    // Offset (into `queue`) of the first keyframe at or after `cut_pts`, or
+    // `queue.len()` if none has been buffered yet. Used to align a
+    // secondary stream's dump-start to a decodable keyframe rather than
+    // merely the first buffer at/after the primary's cut point.
+    fn align_to_keyframe_at_or_after(&self, cut_pts: gst::ClockTime) -> usize {
+        self.gop_starts
+            .iter()
+            .map(|&abs| self.offset(abs))
+            .find(|&off| self.queue[off].timestamp >= cut_pts)
+            .unwrap_or(self.queue.len())
+    }
+}
+
+// An item waiting to be pushed downstream by a stream's srcpad task.
+// Sticky events (STREAM_START/CAPS/SEGMENT/TAG) are routed through the same
+// queue as buffers, rather than pushed directly from the upstream thread,
+// so their ordering relative to buffered/live data is always preserved.
+enum OutputItem {
+    Buffer(gst::Buffer),
+    Event(gst::Event),
+}
+
+// Handoff queue between `sink_chain` and a stream's srcpad streaming task:
+// buffers released on open are moved here instead of being pushed
+// downstream directly, so the upstream-facing chain function never blocks
+// on downstream while the backlog drains.
+struct OutputQueue {
+    queue: VecDeque<OutputItem>,
+    flushing: bool,
+    last_flow_result: Result<gst::FlowSuccess, gst::FlowError>,
+}
+
+impl Default for OutputQueue {
+    fn default() -> Self {
+        Self {
+            queue: VecDeque::new(),
+            flushing: false,
+            last_flow_result: Ok(gst::FlowSuccess::Ok),
         }
     }
 }
 
+// One sink/src pad pair and the backlog that flows between them. The
+// "primary" stream (always pads "sink"/"src") is the one whose first
+// keyframe on open decides the common dump-start point for every other
+// (request-pad) stream, so e.g. an audio track stays in lockstep with it.
+struct StreamPad {
+    name: String,
+    is_primary: bool,
+    sinkpad: gst::Pad,
+    srcpad: gst::Pad,
+    state: Mutex<State>,
+    out_queue: Mutex<OutputQueue>,
+    out_cond: Condvar,
+    // Latest sticky events observed on this pad's sink, regardless of
+    // whether the valve is open or closed; snapshotted into `StoredBuffer`
+    // on every keyframe so a dump can replay the set that was current for
+    // its chosen start point.
+    sticky: Mutex<StickySnapshot>,
+}
+
 mod imp {
     use super::*;
     use glib::subclass::prelude::*;
@@ -56,124 +367,460 @@ mod imp {
 
     pub struct PrerollValve {
         pub settings: Mutex<Settings>,
-        pub state: Mutex<State>,
-        pub srcpad: gst::Pad,
-        pub sinkpad: gst::Pad,
+        pub streams: Mutex<Vec<Arc<StreamPad>>>,
+        // PTS (on the primary stream) that the backlog was cut at on the
+        // last closed->open transition (or at its first live buffer, if it
+        // had nothing buffered). `None` means the primary hasn't seen a
+        // buffer with the valve open since it was last closed, so other
+        // streams keep buffering until the primary establishes this.
+        pub primary_dump_pts: Mutex<Option<gst::ClockTime>>,
+        pub next_pad_index: Mutex<u32>,
     }
 
     impl PrerollValve {
+        // Body of a stream's srcpad streaming task: block until there is a
+        // buffer to push or we're flushing, then push (at most) one buffer
+        // per iteration. `Pad::start_task` re-invokes this in a loop on its
+        // own thread, so this never runs on the upstream chain thread.
+        fn src_task_iteration(&self, stream: &Arc<StreamPad>) {
+            let mut out = stream.out_queue.lock().unwrap();
+            loop {
+                if out.flushing {
+                    return;
+                }
+                if let Some(item) = out.queue.pop_front() {
+                    drop(out);
+                    match item {
+                        OutputItem::Event(event) => {
+                            if self.settings.lock().unwrap().debug {
+                                gst::trace!(CAT, "[{}] Src task pushing event {:?}", stream.name, event.type_());
+                            }
+                            if !stream.srcpad.push_event(event) {
+                                gst::warning!(CAT, "[{}] Src task failed to push sticky event", stream.name);
+                            }
+                        }
+                        OutputItem::Buffer(buffer) => {
+                            if self.settings.lock().unwrap().debug {
+                                gst::trace!(CAT, "[{}] Src task pushing buffer pts={:?}", stream.name, buffer.pts());
+                            }
+                            let result = stream.srcpad.push(buffer);
+                            if let Err(e) = result {
+                                gst::error!(CAT, "[{}] Src task failed to push buffer: {:?}", stream.name, e);
+                            }
+                            let mut out = stream.out_queue.lock().unwrap();
+                            let is_err = result.is_err();
+                            out.last_flow_result = result;
+                            if is_err {
+                                out.flushing = true;
+                                drop(out);
+                                // Latching `flushing` alone isn't enough:
+                                // `Pad::start_task` would just re-invoke this
+                                // closure, which returns immediately on the
+                                // `if out.flushing` guard above, busy-spinning
+                                // until the next FlushStart/state change.
+                                // Pause the task itself, same as the
+                                // FlushStart handler in `sink_event` does.
+                                let _ = stream.srcpad.pause_task();
+                            }
+                        }
+                    }
+                    return;
+                }
+                out = stream.out_cond.wait(out).unwrap();
+            }
+        }
+
+        fn start_src_task(&self, stream: &Arc<StreamPad>) -> Result<(), glib::BoolError> {
+            {
+                let mut out = stream.out_queue.lock().unwrap();
+                out.flushing = false;
+                out.last_flow_result = Ok(gst::FlowSuccess::Ok);
+            }
+            let element = self.obj().clone();
+            let task_stream = stream.clone();
+            stream.srcpad.start_task(move || {
+                element.imp().src_task_iteration(&task_stream);
+            })
+        }
+
+        fn stop_src_task(&self, stream: &Arc<StreamPad>) {
+            {
+                let mut out = stream.out_queue.lock().unwrap();
+                out.flushing = true;
+                out.queue.clear();
+            }
+            stream.out_cond.notify_all();
+            let _ = stream.srcpad.stop_task();
+        }
+
+        // Append `buffer` to `stream`'s backlog and prune whole GOPs from
+        // the front per the `min-history`/`max-history` settings. Used both
+        // for the normal closed-valve path and for streams still waiting on
+        // the primary to establish a common dump-start point.
+        fn buffer_and_prune(
+            &self,
+            stream: &Arc<StreamPad>,
+            buffer: gst::Buffer,
+        ) -> Result<gst::FlowSuccess, gst::FlowError> {
+            let settings = self.settings.lock().unwrap();
+            let mut state = stream.state.lock().unwrap();
+
+            // Identify keyframe
+            // GST_BUFFER_FLAG_DELTA_UNIT == FALSE means keyframe (usually)
+            let is_keyframe = !buffer.flags().contains(gst::BufferFlags::DELTA_UNIT);
+            let pts = buffer.pts().or_else(|| buffer.dts()).unwrap_or(gst::ClockTime::ZERO);
+            let utc = reference_utc(&buffer);
+
+            // Each keyframe starts a new GOP; its sticky snapshot is what
+            // `queue_dump` replays if a dump ever starts from it.
+            let sticky = if is_keyframe {
+                Some(stream.sticky.lock().unwrap().clone())
+            } else {
+                None
+            };
+
+            state.push_buffer(StoredBuffer {
+                buffer,
+                timestamp: pts,
+                utc,
+                is_keyframe,
+                sticky,
+            });
+
+            // Reference "now" for the backlog: relative to the stream head
+            // (PTS) by default, or UTC wall-clock time when `history-mode`
+            // is set to `utc` and the buffer carries a reference timestamp.
+            let current_ts = match settings.history_mode {
+                HistoryMode::Pts => pts,
+                HistoryMode::Utc => utc.unwrap_or(pts),
+            };
+            state.prune(&settings, current_ts);
+
+            Ok(gst::FlowSuccess::Ok)
+        }
+
+        // Queue a contiguous run of stored buffers for `stream`'s src task
+        // to drain: replays the first buffer's sticky snapshot (if any) so
+        // downstream can decode it standalone, flags that buffer DISCONT
+        // since it resumes a gap in the live stream, then queues a fresh
+        // SEGMENT from the stream's current sticky state so the live
+        // portion that follows continues from a coherent running time.
+        fn queue_dump(&self, stream: &StreamPad, buffers: impl Iterator<Item = StoredBuffer>) {
+            let mut out = stream.out_queue.lock().unwrap();
+            for (i, stored) in buffers.enumerate() {
+                if i == 0 {
+                    if let Some(sticky) = stored.sticky {
+                        for event in [sticky.stream_start, sticky.caps, sticky.segment, sticky.tag]
+                            .into_iter()
+                            .flatten()
+                        {
+                            out.queue.push_back(OutputItem::Event(event));
+                        }
+                    }
+                }
+                let mut buffer = stored.buffer;
+                if i == 0 {
+                    let buffer_mut = buffer.make_mut();
+                    buffer_mut.set_flags(buffer_mut.flags() | gst::BufferFlags::DISCONT);
+                }
+                out.queue.push_back(OutputItem::Buffer(buffer));
+            }
+            if let Some(segment) = stream.sticky.lock().unwrap().segment.clone() {
+                out.queue.push_back(OutputItem::Event(segment));
+            }
+        }
+
+        // Drop every non-primary stream's backlog buffers ahead of its
+        // first keyframe at/after `cut_pts` and hand off the remainder to
+        // that stream's src task (a stream with no such keyframe yet is
+        // left as it is),
+        // so all outputs begin at the same common running time as the
+        // primary's dump-start keyframe.
+        fn flush_other_streams(&self, primary: &Arc<StreamPad>, cut_pts: gst::ClockTime) {
+            let streams = self.streams.lock().unwrap();
+            for other in streams.iter() {
+                if Arc::ptr_eq(other, primary) {
+                    continue;
+                }
+
+                let mut state = other.state.lock().unwrap();
+                if state.queue.is_empty() {
+                    continue;
+                }
+
+                // Align to the first *keyframe* at/after the cut point, not
+                // merely the first buffer, so this stream's dump is always
+                // decodable standalone. If none has been buffered yet, leave
+                // this stream's backlog alone; its own next `sink_chain`
+                // call will align and dump once a qualifying keyframe
+                // arrives (see the non-primary branch below).
+                let align_off = state.align_to_keyframe_at_or_after(cut_pts);
+                if align_off >= state.queue.len() {
+                    continue;
+                }
+                for _ in 0..align_off {
+                    if let Some(dropped) = state.queue.pop_front() {
+                        state.cur_bytes = state.cur_bytes.saturating_sub(dropped.buffer.size() as u64);
+                    }
+                }
+
+                gst::info!(CAT, "Aligning '{}' backlog to primary cut point {:?}", other.name, cut_pts);
+                self.queue_dump(other, state.queue.drain(..));
+                state.gop_starts.clear();
+                state.dropped = 0;
+                state.cur_bytes = 0;
+                drop(state);
+                other.out_cond.notify_all();
+            }
+        }
+
         fn sink_chain(
             &self,
+            stream: &Arc<StreamPad>,
             _pad: &gst::Pad,
-            _element: &super::PrerollValve,
             buffer: gst::Buffer,
         ) -> Result<gst::FlowSuccess, gst::FlowError> {
-            let settings = self.settings.lock().unwrap();
-            let mut state = self.state.lock().unwrap();
+            let open = {
+                let settings = self.settings.lock().unwrap();
+                if settings.debug {
+                    gst::trace!(CAT, "[{}] Received buffer: pts={:?}, dts={:?}", stream.name, buffer.pts(), buffer.dts());
+                }
+                settings.open
+            };
 
-            // Check debug property or GST log level
-            if settings.debug {
-                 gst::trace!(CAT, "Received buffer: pts={:?}, dts={:?}", buffer.pts(), buffer.dts());
+            if !open {
+                // Valve is closed (default): store and prune.
+                return self.buffer_and_prune(stream, buffer);
             }
 
-            if settings.open {
-                // If we have data in queue, we must dump it first
-                // This happens on the transition from closed -> open
-                // Since we are in the chain function, we are serialized with upstream
+            // If the src task hit a flow error (or we're flushing), stop
+            // accepting buffers and surface that to upstream instead of
+            // silently swallowing them.
+            if let Err(e) = &stream.out_queue.lock().unwrap().last_flow_result {
+                return Err(*e);
+            }
+
+            if stream.is_primary {
+                // If we have data in queue, hand it off to the src task
+                // first. This happens on the transition from closed -> open.
+                // We only ever copy buffer refs here; the task does the
+                // actual (potentially slow) pushing on its own thread, so
+                // upstream is never blocked waiting for downstream.
+                let mut state = stream.state.lock().unwrap();
                 if !state.queue.is_empty() {
-                    gst::info!(CAT, "Valve opened. Dumping {} buffered frames.", state.queue.len());
-                    
-                    // Find first keyframe index
-                    let mut start_index = None;
-                    // Search forwards for the first keyframe to maximize preroll
-                    // We need to start from a keyframe so the decoder can decode
-                    for (i, stored) in state.queue.iter().enumerate() {
-                        if stored.is_keyframe {
-                            start_index = Some(i);
-                            break;
-                        }
+                    gst::info!(CAT, "Valve opened. Handing off {} buffered frames on '{}' to the src task.", state.queue.len(), stream.name);
+
+                    if state.gop_starts.is_empty() {
+                        gst::warning!(CAT, "No keyframe found in primary buffer, dumping from start");
                     }
-                    
-                    // Use first keyframe if found, otherwise dump from start
-                    let idx = start_index.unwrap_or_else(|| {
-                        gst::warning!(CAT, "No keyframe found in buffer, dumping from start");
-                        0
-                    });
-                    
-                    let frames_to_dump = state.queue.len() - idx;
-                    gst::info!(CAT, "Starting dump from index {} (is_keyframe={}), dumping {} frames", 
-                        idx, 
-                        state.queue.get(idx).map(|b| b.is_keyframe).unwrap_or(false),
-                        frames_to_dump
-                    );
 
-                    // Dump buffers
-                    for i in idx..state.queue.len() {
-                        if let Some(stored) = state.queue.get(i) {
-                             if settings.debug {
-                                gst::trace!(CAT, "Pushing stored buffer pts={:?}", stored.buffer.pts());
-                            }
-                            let buf_to_push = stored.buffer.clone();
-                            if let Err(e) = self.srcpad.push(buf_to_push) {
-                                gst::error!(CAT, "Failed to push stored buffer: {:?}", e);
-                                state.queue.clear();
-                                return Err(e);
-                            }
+                    let (from, to) = {
+                        let settings = self.settings.lock().unwrap();
+                        let wants_range = settings.dump_from != 0 || settings.dump_to != 0;
+                        // dump_range only ever reads reference_ts off the
+                        // keyframe offsets in gop_starts, which falls back to
+                        // raw PTS for any that lack a `ReferenceTimestampMeta`.
+                        // A partial mix of real UTC and PTS-fallback keyframes
+                        // breaks the monotonic ordering take_while/find rely
+                        // on just as badly as having none at all, so require
+                        // every keyframe to carry one, not just any buffer.
+                        let keyframes_lack_utc = !state.gop_starts.is_empty()
+                            && state
+                                .gop_starts
+                                .iter()
+                                .map(|&abs| state.offset(abs))
+                                .any(|off| state.queue[off].utc.is_none());
+                        if wants_range && keyframes_lack_utc {
+                            gst::warning!(CAT, "[{}] dump-from/dump-to set but not every buffered keyframe carries a UTC reference timestamp; ignoring range", stream.name);
+                            (None, None)
+                        } else {
+                            (
+                                (settings.dump_from != 0).then(|| gst::ClockTime::from_mseconds(settings.dump_from)),
+                                (settings.dump_to != 0).then(|| gst::ClockTime::from_mseconds(settings.dump_to)),
+                            )
                         }
-                    }
+                    };
+                    // GOP-aware pruning guarantees the queue always starts on
+                    // a keyframe, so with no `dump-from`/`dump-to` bound this
+                    // just selects [0, len).
+                    let (idx, end_idx) = state.dump_range(from, to);
+
+                    let cut_pts = state.queue.get(idx).map(|b| b.timestamp).unwrap_or(gst::ClockTime::ZERO);
+                    let frames_to_dump = end_idx.saturating_sub(idx);
+                    gst::info!(CAT, "Starting dump from index {} to {} (cut_pts={:?}), queuing {} frames",
+                        idx, end_idx, cut_pts, frames_to_dump
+                    );
+
+                    self.queue_dump(stream, state.queue.drain(idx..end_idx));
                     state.queue.clear();
-                }
+                    state.gop_starts.clear();
+                    state.dropped = 0;
+                    state.cur_bytes = 0;
+                    drop(state);
 
-                // Forward the current live buffer
-                drop(state);
-                drop(settings);
-                self.srcpad.push(buffer)
+                    *self.primary_dump_pts.lock().unwrap() = Some(cut_pts);
+                    self.flush_other_streams(stream, cut_pts);
+                } else {
+                    // Opened with nothing buffered on the primary: the
+                    // first live primary buffer sets the common cut point.
+                    drop(state);
+                    let mut dump_pts = self.primary_dump_pts.lock().unwrap();
+                    if dump_pts.is_none() {
+                        let cut_pts = buffer.pts().or_else(|| buffer.dts()).unwrap_or(gst::ClockTime::ZERO);
+                        *dump_pts = Some(cut_pts);
+                        drop(dump_pts);
+                        self.flush_other_streams(stream, cut_pts);
+                    }
+                }
             } else {
-                // Valve is closed (default)
-                // Store incoming buffers
-                
-                // Identify keyframe
-                // GST_BUFFER_FLAG_DELTA_UNIT == FALSE means keyframe (usually)
-                let is_keyframe = !buffer.flags().contains(gst::BufferFlags::DELTA_UNIT);
-                let pts = buffer.pts().or_else(|| buffer.dts()).unwrap_or(gst::ClockTime::ZERO);
-
-                let stored = StoredBuffer {
-                    buffer: buffer, // ownership moved to struct
-                    timestamp: pts,
-                    is_keyframe,
+                // Secondary stream: only start releasing once the primary
+                // has established the common cut point *and* this stream
+                // has buffered a keyframe at or after it; otherwise keep
+                // buffering this pad as if the valve were still closed, so
+                // it doesn't race ahead of (or fall behind) the primary, and
+                // never starts a dump on an undecodable delta frame. Once
+                // this stream's backlog is empty (already released, or
+                // nothing was buffered when the cut point was set), live
+                // buffers pass straight through without this alignment.
+                let cut_pts = *self.primary_dump_pts.lock().unwrap();
+                let cut_pts = match cut_pts {
+                    Some(cut_pts) => cut_pts,
+                    None => return self.buffer_and_prune(stream, buffer),
                 };
-                
-                state.queue.push_back(stored);
-
-                // Prune old buffers
-                let max_history = gst::ClockTime::from_mseconds(settings.max_history);
-                // We use the timestamp of the *latest* buffer (pts) as reference current time?
-                // Or system time?
-                // "current_timestamp - buffer.timestamp <= max_history". 
-                // Usually this implies relative to the stream head.
-                let current_ts = pts;
-                
-                while let Some(front) = state.queue.front() {
-                    if current_ts > front.timestamp && (current_ts - front.timestamp) > max_history {
-                        state.queue.pop_front();
-                    } else {
-                        break;
+
+                let mut state = stream.state.lock().unwrap();
+                if !state.queue.is_empty() {
+                    let align_off = state.align_to_keyframe_at_or_after(cut_pts);
+                    if align_off >= state.queue.len() {
+                        drop(state);
+                        return self.buffer_and_prune(stream, buffer);
                     }
+                    for _ in 0..align_off {
+                        if let Some(dropped) = state.queue.pop_front() {
+                            state.cur_bytes = state.cur_bytes.saturating_sub(dropped.buffer.size() as u64);
+                        }
+                    }
+                    self.queue_dump(stream, state.queue.drain(..));
+                    state.gop_starts.clear();
+                    state.dropped = 0;
+                    state.cur_bytes = 0;
                 }
-                
-                Ok(gst::FlowSuccess::Ok)
             }
+
+            // Queue the live buffer behind whatever backlog was just
+            // released; the task drains the front at its own pace while we
+            // keep buffering new live frames into the tail here.
+            if self.settings.lock().unwrap().debug {
+                gst::trace!(CAT, "[{}] Queuing live buffer for src task: pts={:?}", stream.name, buffer.pts());
+            }
+            stream.out_queue.lock().unwrap().queue.push_back(OutputItem::Buffer(buffer));
+            stream.out_cond.notify_all();
+            Ok(gst::FlowSuccess::Ok)
         }
 
         fn sink_event(
             &self,
+            stream: &Arc<StreamPad>,
             _pad: &gst::Pad,
-            _element: &super::PrerollValve,
             event: gst::Event,
         ) -> bool {
-            // Forward all incoming events (e.g., CAPS/EOS/FLUSH) to src pad to
-            // keep negotiation working.
-            self.srcpad.push_event(event)
+            match event.view() {
+                // Abort the src task's wait, drop anything still queued for
+                // it, and pause the task itself so it isn't re-invoked in a
+                // tight busy-loop with nothing to do for the rest of the
+                // flush window. Flushes act out-of-band: push directly
+                // rather than going through the (now paused) out_queue.
+                gst::EventView::FlushStart(_) => {
+                    {
+                        let mut out = stream.out_queue.lock().unwrap();
+                        out.flushing = true;
+                        out.queue.clear();
+                    }
+                    stream.out_cond.notify_all();
+                    let _ = stream.srcpad.pause_task();
+                    return stream.srcpad.push_event(event);
+                }
+                gst::EventView::FlushStop(_) => {
+                    {
+                        let mut out = stream.out_queue.lock().unwrap();
+                        out.flushing = false;
+                        out.last_flow_result = Ok(gst::FlowSuccess::Ok);
+                    }
+                    if let Err(e) = self.start_src_task(stream) {
+                        gst::error!(CAT, "[{}] Failed to resume src task after flush: {:?}", stream.name, e);
+                    }
+                    return stream.srcpad.push_event(event);
+                }
+                // Track the sticky events current on this pad (used to
+                // snapshot keyframes for dumping, see `buffer_and_prune`).
+                // While the valve is closed these are intercepted rather
+                // than forwarded: only the snapshot captured for the
+                // chosen dump-start keyframe is replayed (by `queue_dump`)
+                // once it opens. While open, route them through the same
+                // output queue as buffers instead of pushing directly, so
+                // their order relative to buffered/live data is preserved.
+                gst::EventView::StreamStart(_) | gst::EventView::Caps(_) | gst::EventView::Segment(_)
+                | gst::EventView::Tag(_) => {
+                    {
+                        let mut sticky = stream.sticky.lock().unwrap();
+                        match event.view() {
+                            gst::EventView::StreamStart(_) => sticky.stream_start = Some(event.clone()),
+                            gst::EventView::Caps(_) => sticky.caps = Some(event.clone()),
+                            gst::EventView::Segment(_) => sticky.segment = Some(event.clone()),
+                            gst::EventView::Tag(_) => sticky.tag = Some(event.clone()),
+                            _ => unreachable!(),
+                        }
+                    }
+                    if self.settings.lock().unwrap().open {
+                        stream.out_queue.lock().unwrap().queue.push_back(OutputItem::Event(event));
+                        stream.out_cond.notify_all();
+                    }
+                    return true;
+                }
+                _ => {}
+            }
+
+            // Every other serialized event (EOS, GAP, custom, ...) must
+            // preserve ordering relative to buffers/sticky events still
+            // waiting in `out_queue`, so route it through the src task
+            // instead of pushing directly from this (upstream) thread.
+            stream.out_queue.lock().unwrap().queue.push_back(OutputItem::Event(event));
+            stream.out_cond.notify_all();
+            true
+        }
+
+        // Wire up chain/event functions that close over `stream`, so we
+        // don't need a pad->stream lookup table on every buffer/event.
+        fn bind_stream_pad_functions(&self, stream: &Arc<StreamPad>) {
+            let chain_stream = stream.clone();
+            stream.sinkpad.set_chain_function(move |pad, parent, buffer| {
+                PrerollValve::catch_panic_pad_function(
+                    parent,
+                    || Err(gst::FlowError::Error),
+                    |preroll| preroll.sink_chain(&chain_stream, pad, buffer),
+                )
+            });
+
+            let event_stream = stream.clone();
+            stream.sinkpad.set_event_function(move |pad, parent, event| {
+                PrerollValve::catch_panic_pad_function(
+                    parent,
+                    || false,
+                    |preroll| preroll.sink_event(&event_stream, pad, event),
+                )
+            });
+        }
+
+        // Read the `current-level-*` properties off the primary stream's
+        // backlog, since element-global settings like `max-history` are
+        // primarily about bounding that stream's memory use.
+        fn primary_level(&self, f: impl FnOnce(&State) -> u64) -> u64 {
+            let streams = self.streams.lock().unwrap();
+            streams
+                .iter()
+                .find(|s| s.is_primary)
+                .map(|s| f(&s.state.lock().unwrap()))
+                .unwrap_or(0)
         }
     }
 
@@ -183,35 +830,12 @@ mod imp {
         type Type = super::PrerollValve;
         type ParentType = gst::Element;
 
-        fn with_class(klass: &Self::Class) -> Self {
-            let templ_sink = klass.pad_template("sink").unwrap();
-            let templ_src = klass.pad_template("src").unwrap();
-
-            let sinkpad = gst::Pad::builder_from_template(&templ_sink)
-                .chain_function(|pad, parent, buffer| {
-                    PrerollValve::catch_panic_pad_function(
-                        parent,
-                        || Err(gst::FlowError::Error),
-                        |preroll| preroll.sink_chain(pad, &preroll.obj(), buffer),
-                    )
-                })
-                .event_function(|pad, parent, event| {
-                    PrerollValve::catch_panic_pad_function(
-                        parent,
-                        || false,
-                        |preroll| preroll.sink_event(pad, &preroll.obj(), event),
-                    )
-                })
-                .build();
-
-            let srcpad = gst::Pad::builder_from_template(&templ_src)
-                .build();
-
+        fn with_class(_klass: &Self::Class) -> Self {
             Self {
                 settings: Mutex::new(Settings::default()),
-                state: Mutex::new(State::default()),
-                sinkpad,
-                srcpad,
+                streams: Mutex::new(Vec::new()),
+                primary_dump_pts: Mutex::new(None),
+                next_pad_index: Mutex::new(0),
             }
         }
     }
@@ -220,8 +844,27 @@ mod imp {
         fn constructed(&self) {
             self.parent_constructed();
             let obj = self.obj();
-            obj.add_pad(&self.sinkpad).unwrap();
-            obj.add_pad(&self.srcpad).unwrap();
+
+            let templ_sink = obj.class().pad_template("sink").unwrap();
+            let templ_src = obj.class().pad_template("src").unwrap();
+            let sinkpad = gst::Pad::builder_from_template(&templ_sink).build();
+            let srcpad = gst::Pad::builder_from_template(&templ_src).build();
+
+            let primary = Arc::new(StreamPad {
+                name: "sink".to_string(),
+                is_primary: true,
+                sinkpad: sinkpad.clone(),
+                srcpad: srcpad.clone(),
+                state: Mutex::new(State::default()),
+                out_queue: Mutex::new(OutputQueue::default()),
+                out_cond: Condvar::new(),
+                sticky: Mutex::new(StickySnapshot::default()),
+            });
+            self.bind_stream_pad_functions(&primary);
+
+            obj.add_pad(&sinkpad).unwrap();
+            obj.add_pad(&srcpad).unwrap();
+            self.streams.lock().unwrap().push(primary);
         }
 
         fn properties() -> &'static [glib::ParamSpec] {
@@ -234,6 +877,13 @@ mod imp {
                         .mutable_ready()
                         .mutable_playing()
                         .build(),
+                    glib::ParamSpecUInt64::builder("min-history")
+                        .nick("Min History")
+                        .blurb("Minimum history in milliseconds to retain before pruning a GOP")
+                        .default_value(DEFAULT_MIN_HISTORY)
+                        .mutable_ready()
+                        .mutable_playing()
+                        .build(),
                     glib::ParamSpecUInt64::builder("max-history")
                         .nick("Max History")
                         .blurb("Max history in milliseconds to buffer")
@@ -248,6 +898,55 @@ mod imp {
                         .mutable_ready()
                         .mutable_playing()
                         .build(),
+                    glib::ParamSpecEnum::builder_with_default("history-mode", HistoryMode::default())
+                        .nick("History Mode")
+                        .blurb("Clock that min/max-history are measured against (dump-from/dump-to always use UTC)")
+                        .mutable_ready()
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecUInt64::builder("dump-from")
+                        .nick("Dump From")
+                        .blurb("UTC time (UNIX ms) to start the backlog dump from, 0 = from the earliest buffered keyframe")
+                        .default_value(DEFAULT_DUMP_FROM)
+                        .mutable_ready()
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecUInt64::builder("dump-to")
+                        .nick("Dump To")
+                        .blurb("UTC time (UNIX ms) to end the backlog dump at, 0 = through the most recent buffer")
+                        .default_value(DEFAULT_DUMP_TO)
+                        .mutable_ready()
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecUInt64::builder("max-size-bytes")
+                        .nick("Max Size Bytes")
+                        .blurb("Max amount of backlog to buffer, in bytes, 0 = disabled")
+                        .default_value(DEFAULT_MAX_SIZE_BYTES)
+                        .mutable_ready()
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecUInt64::builder("max-size-buffers")
+                        .nick("Max Size Buffers")
+                        .blurb("Max amount of backlog to buffer, in buffers, 0 = disabled")
+                        .default_value(DEFAULT_MAX_SIZE_BUFFERS)
+                        .mutable_ready()
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecUInt64::builder("current-level-time")
+                        .nick("Current Level Time")
+                        .blurb("Current amount of backlog buffered on the primary stream, in milliseconds")
+                        .read_only()
+                        .build(),
+                    glib::ParamSpecUInt64::builder("current-level-bytes")
+                        .nick("Current Level Bytes")
+                        .blurb("Current amount of backlog buffered on the primary stream, in bytes")
+                        .read_only()
+                        .build(),
+                    glib::ParamSpecUInt64::builder("current-level-buffers")
+                        .nick("Current Level Buffers")
+                        .blurb("Current amount of backlog buffered on the primary stream, in buffers")
+                        .read_only()
+                        .build(),
                 ]
             });
             PROPERTIES.as_ref()
@@ -256,20 +955,56 @@ mod imp {
         fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
             let mut settings = self.settings.lock().unwrap();
             match pspec.name() {
-                "open" => settings.open = value.get().expect("type checked upstream"),
+                "open" => {
+                    let open = value.get().expect("type checked upstream");
+                    // Reset the shared cut point whenever the valve closes,
+                    // so the next open re-derives it from the primary's
+                    // fresh backlog instead of an old one.
+                    if settings.open && !open {
+                        *self.primary_dump_pts.lock().unwrap() = None;
+                    }
+                    settings.open = open;
+                }
+                "min-history" => settings.min_history = value.get().expect("type checked upstream"),
                 "max-history" => settings.max_history = value.get().expect("type checked upstream"),
                 "debug" => settings.debug = value.get().expect("type checked upstream"),
+                "history-mode" => settings.history_mode = value.get().expect("type checked upstream"),
+                "dump-from" => settings.dump_from = value.get().expect("type checked upstream"),
+                "dump-to" => settings.dump_to = value.get().expect("type checked upstream"),
+                "max-size-bytes" => settings.max_size_bytes = value.get().expect("type checked upstream"),
+                "max-size-buffers" => settings.max_size_buffers = value.get().expect("type checked upstream"),
                 _ => unimplemented!(),
             }
         }
 
         fn property(&self, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
-            let settings = self.settings.lock().unwrap();
             match pspec.name() {
-                "open" => settings.open.to_value(),
-                "max-history" => settings.max_history.to_value(),
-                "debug" => settings.debug.to_value(),
-                _ => unimplemented!(),
+                "current-level-time" => self.primary_level(|state| {
+                    match (state.queue.front(), state.queue.back()) {
+                        (Some(first), Some(last)) if last.timestamp >= first.timestamp => {
+                            (last.timestamp - first.timestamp).mseconds()
+                        }
+                        _ => 0,
+                    }
+                })
+                .to_value(),
+                "current-level-bytes" => self.primary_level(|state| state.cur_bytes).to_value(),
+                "current-level-buffers" => self.primary_level(|state| state.queue.len() as u64).to_value(),
+                name => {
+                    let settings = self.settings.lock().unwrap();
+                    match name {
+                        "open" => settings.open.to_value(),
+                        "min-history" => settings.min_history.to_value(),
+                        "max-history" => settings.max_history.to_value(),
+                        "debug" => settings.debug.to_value(),
+                        "history-mode" => settings.history_mode.to_value(),
+                        "dump-from" => settings.dump_from.to_value(),
+                        "dump-to" => settings.dump_to.to_value(),
+                        "max-size-bytes" => settings.max_size_bytes.to_value(),
+                        "max-size-buffers" => settings.max_size_buffers.to_value(),
+                        _ => unimplemented!(),
+                    }
+                }
             }
         }
     }
@@ -282,7 +1017,7 @@ mod imp {
                 gst::subclass::ElementMetadata::new(
                     "Preroll Valve",
                     "Generic/Filter/Video",
-                    "Buffers video and dumps on command",
+                    "Buffers video (and optionally other synchronized streams) and dumps on command",
                     "Cursor AI",
                 )
             });
@@ -292,7 +1027,7 @@ mod imp {
         fn pad_templates() -> &'static [gst::PadTemplate] {
             static PAD_TEMPLATES: Lazy<Vec<gst::PadTemplate>> = Lazy::new(|| {
                 let caps = gst::Caps::new_any(); // Accepting ANY for flexibility, specifically H264
-                
+
                 vec![
                     gst::PadTemplate::new(
                         "sink",
@@ -308,10 +1043,120 @@ mod imp {
                         &caps,
                     )
                     .unwrap(),
+                    // Additional synchronized streams (e.g. audio) are added
+                    // as request sink pads; the matching src pad is created
+                    // automatically with the same index.
+                    gst::PadTemplate::new(
+                        "sink_%u",
+                        gst::PadDirection::Sink,
+                        gst::PadPresence::Request,
+                        &caps,
+                    )
+                    .unwrap(),
+                    gst::PadTemplate::new(
+                        "src_%u",
+                        gst::PadDirection::Src,
+                        gst::PadPresence::Request,
+                        &caps,
+                    )
+                    .unwrap(),
                 ]
             });
             PAD_TEMPLATES.as_ref()
         }
+
+        fn request_new_pad(
+            &self,
+            templ: &gst::PadTemplate,
+            name: Option<&str>,
+            _caps: Option<&gst::Caps>,
+        ) -> Option<gst::Pad> {
+            if templ.name_template() != "sink_%u" {
+                gst::error!(CAT, "Unsupported request pad template '{}'", templ.name_template());
+                return None;
+            }
+
+            let mut next_index = self.next_pad_index.lock().unwrap();
+            let index = *next_index;
+            *next_index += 1;
+            drop(next_index);
+
+            let sink_name = name.map(|s| s.to_string()).unwrap_or_else(|| format!("sink_{}", index));
+            let suffix = sink_name.strip_prefix("sink_").unwrap_or(&index.to_string()).to_string();
+            let src_name = format!("src_{}", suffix);
+
+            let obj = self.obj();
+            let src_templ = obj.class().pad_template("src_%u").unwrap();
+            let sinkpad = gst::Pad::from_template(templ, Some(&sink_name));
+            let srcpad = gst::Pad::from_template(&src_templ, Some(&src_name));
+
+            let stream = Arc::new(StreamPad {
+                name: sink_name,
+                is_primary: false,
+                sinkpad: sinkpad.clone(),
+                srcpad: srcpad.clone(),
+                state: Mutex::new(State::default()),
+                out_queue: Mutex::new(OutputQueue::default()),
+                out_cond: Condvar::new(),
+                sticky: Mutex::new(StickySnapshot::default()),
+            });
+            self.bind_stream_pad_functions(&stream);
+
+            obj.add_pad(&sinkpad).ok()?;
+            obj.add_pad(&srcpad).ok()?;
+            self.streams.lock().unwrap().push(stream.clone());
+
+            // If the element is already running, the global state-change
+            // handler already started tasks for the streams that existed at
+            // the time; start this late-comer's task ourselves.
+            if obj.current_state() >= gst::State::Paused {
+                if let Err(e) = self.start_src_task(&stream) {
+                    gst::error!(CAT, "Failed to start src task for '{}': {:?}", stream.name, e);
+                }
+            }
+
+            Some(sinkpad)
+        }
+
+        fn release_pad(&self, pad: &gst::Pad) {
+            let stream = {
+                let mut streams = self.streams.lock().unwrap();
+                let pos = streams.iter().position(|s| &s.sinkpad == pad);
+                pos.map(|pos| streams.remove(pos))
+            };
+
+            if let Some(stream) = stream {
+                self.stop_src_task(&stream);
+                let _ = self.obj().remove_pad(&stream.sinkpad);
+                let _ = self.obj().remove_pad(&stream.srcpad);
+            }
+        }
+
+        fn change_state(
+            &self,
+            transition: gst::StateChange,
+        ) -> Result<gst::StateChangeSuccess, gst::StateChangeError> {
+            if transition == gst::StateChange::ReadyToPaused {
+                let streams = self.streams.lock().unwrap().clone();
+                for stream in &streams {
+                    self.start_src_task(stream).map_err(|e| {
+                        gst::error!(CAT, "Failed to start src task for '{}': {:?}", stream.name, e);
+                        gst::StateChangeError
+                    })?;
+                }
+            }
+
+            let success = self.parent_change_state(transition)?;
+
+            if transition == gst::StateChange::PausedToReady {
+                let streams = self.streams.lock().unwrap().clone();
+                for stream in &streams {
+                    self.stop_src_task(stream);
+                }
+            }
+
+            Ok(success)
+        }
     }
 }
 
@@ -337,3 +1182,172 @@ static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
     )
 });
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored_buffer(pts_ms: u64, size: usize, is_keyframe: bool) -> StoredBuffer {
+        let mut buffer = gst::Buffer::with_size(size).unwrap();
+        buffer.get_mut().unwrap().set_pts(gst::ClockTime::from_mseconds(pts_ms));
+        StoredBuffer {
+            buffer,
+            timestamp: gst::ClockTime::from_mseconds(pts_ms),
+            utc: None,
+            is_keyframe,
+            sticky: None,
+        }
+    }
+
+    // Push directly onto `State`, bypassing `buffer_and_prune`/`StreamPad`
+    // (which need a full element + pads), so the pure GOP/prune/dump-range
+    // logic can be exercised on its own.
+    fn push(state: &mut State, stored: StoredBuffer) {
+        state.push_buffer(stored);
+    }
+
+    #[test]
+    fn prune_retains_min_history_even_past_max_history() {
+        gst::init().unwrap();
+        let mut state = State::default();
+        push(&mut state, stored_buffer(0, 10, true));
+        push(&mut state, stored_buffer(1000, 10, true));
+        push(&mut state, stored_buffer(2000, 10, true));
+
+        let settings = Settings {
+            min_history: 1500,
+            max_history: 500,
+            ..Settings::default()
+        };
+
+        // Dropping the oldest GOP would leave only 1000ms of history
+        // (2000 - 1000), below the 1500ms min_history floor, so nothing
+        // should be pruned even though max_history (500ms) is exceeded.
+        state.prune(&settings, gst::ClockTime::from_mseconds(2000));
+        assert_eq!(state.queue.len(), 3);
+    }
+
+    #[test]
+    fn prune_drops_whole_gops_past_max_history_when_min_history_allows() {
+        gst::init().unwrap();
+        let mut state = State::default();
+        push(&mut state, stored_buffer(0, 10, true));
+        push(&mut state, stored_buffer(1000, 10, true));
+        push(&mut state, stored_buffer(2000, 10, true));
+
+        let settings = Settings {
+            min_history: 0,
+            max_history: 500,
+            ..Settings::default()
+        };
+
+        state.prune(&settings, gst::ClockTime::from_mseconds(2000));
+        // The oldest GOP is dropped. The 1000ms GOP is also past
+        // max_history, but dropping it would leave only the keyframe at
+        // "now" (2000ms), and prune never drops a GOP whose successor
+        // starts at `current_ts`; the newest GOP can never be dropped
+        // since there's nothing newer than it yet.
+        assert_eq!(state.queue.len(), 2);
+        assert_eq!(state.queue.front().unwrap().timestamp, gst::ClockTime::from_mseconds(1000));
+    }
+
+    #[test]
+    fn prune_enforces_max_size_buffers_even_within_min_history() {
+        gst::init().unwrap();
+        let mut state = State::default();
+        push(&mut state, stored_buffer(0, 10, true));
+        push(&mut state, stored_buffer(1000, 10, true));
+        push(&mut state, stored_buffer(2000, 10, true));
+
+        let settings = Settings {
+            min_history: 10_000, // would otherwise retain everything
+            max_history: 10_000,
+            max_size_buffers: 2,
+            ..Settings::default()
+        };
+
+        state.prune(&settings, gst::ClockTime::from_mseconds(2000));
+        // The hard buffer-count cap overrides min_history.
+        assert_eq!(state.queue.len(), 2);
+    }
+
+    #[test]
+    fn dump_range_selects_gops_overlapping_from_to() {
+        gst::init().unwrap();
+        let mut state = State::default();
+        for pts_ms in [0u64, 1000, 2000, 3000] {
+            let mut stored = stored_buffer(pts_ms, 10, true);
+            stored.utc = Some(gst::ClockTime::from_mseconds(pts_ms));
+            push(&mut state, stored);
+        }
+
+        let from = Some(gst::ClockTime::from_mseconds(1500));
+        let to = Some(gst::ClockTime::from_mseconds(2500));
+        let (start, end) = state.dump_range(from, to);
+
+        // The [1500, 2500]ms window overlaps the GOPs starting at 1000ms
+        // and 2000ms. Selection is keyframe-aligned, so it starts at the
+        // keyframe at/before `from` (1000ms) rather than mid-GOP, and ends
+        // before the first keyframe after `to` (3000ms).
+        assert_eq!((start, end), (1, 3));
+    }
+
+    #[test]
+    fn dump_range_with_no_bounds_covers_everything() {
+        gst::init().unwrap();
+        let mut state = State::default();
+        push(&mut state, stored_buffer(0, 10, true));
+        push(&mut state, stored_buffer(40, 10, false));
+        push(&mut state, stored_buffer(1000, 10, true));
+
+        assert_eq!(state.dump_range(None, None), (0, 3));
+    }
+
+    #[test]
+    fn align_to_keyframe_at_or_after_skips_delta_frames() {
+        gst::init().unwrap();
+        let mut state = State::default();
+        push(&mut state, stored_buffer(0, 10, true));
+        push(&mut state, stored_buffer(1000, 10, true));
+        push(&mut state, stored_buffer(1040, 10, false));
+        push(&mut state, stored_buffer(2000, 10, true));
+
+        // A cut point that lands mid-GOP (between the keyframe at 1000ms
+        // and its first delta frame) must align to the *next* keyframe,
+        // not the first buffer at/after it.
+        let cut_pts = gst::ClockTime::from_mseconds(1020);
+        assert_eq!(state.align_to_keyframe_at_or_after(cut_pts), 3);
+
+        // No keyframe at/after this cut point has been buffered yet.
+        let too_late = gst::ClockTime::from_mseconds(5000);
+        assert_eq!(state.align_to_keyframe_at_or_after(too_late), state.queue.len());
+    }
+
+    #[test]
+    fn push_buffer_strips_leading_deltas_before_first_keyframe() {
+        gst::init().unwrap();
+        let mut state = State::default();
+        // Valve closed mid-GOP: delta frames arrive before any keyframe
+        // has ever been recorded.
+        push(&mut state, stored_buffer(0, 10, false));
+        push(&mut state, stored_buffer(40, 10, false));
+        push(&mut state, stored_buffer(80, 10, true));
+        push(&mut state, stored_buffer(120, 10, false));
+        push(&mut state, stored_buffer(1080, 10, true));
+        push(&mut state, stored_buffer(2000, 10, true));
+
+        // The orphaned deltas can never anchor a dump, so they're dropped
+        // immediately: the queue starts on the first keyframe, matching
+        // `pop_front_gop`'s assumption that `gop_starts[0]` is always at
+        // offset 0.
+        assert_eq!(state.queue.len(), 4);
+        assert_eq!(state.queue.front().unwrap().timestamp, gst::ClockTime::from_mseconds(80));
+        assert_eq!(state.offset(state.gop_starts[0]), 0);
+
+        // Pruning afterwards still drops whole GOPs correctly, proving
+        // `gop_starts`/`dropped` didn't desync from the real queue.
+        let settings = Settings { min_history: 0, max_history: 500, ..Settings::default() };
+        state.prune(&settings, gst::ClockTime::from_mseconds(2000));
+        assert_eq!(state.queue.len(), 2);
+        assert_eq!(state.queue.front().unwrap().timestamp, gst::ClockTime::from_mseconds(1080));
+    }
+}